@@ -0,0 +1,373 @@
+use regex::Regex;
+use rusqlite::{Connection, OpenFlags, OptionalExtension, NO_PARAMS};
+
+use chainstate::burn::ConsensusHash;
+use chainstate::stacks::StacksBlockId;
+use net::StacksMessageCodec;
+use util::hash::{hex_bytes, to_hex, Hash160};
+use vm::types::{QualifiedContractIdentifier, Value};
+
+use super::onchain::{AttachmentProof, OnchainInventoryLookup};
+use super::{
+    Attachment, AtlasConfig, AttachmentInstance, BNS_NAME_REGEX, ATTACHMENTS_PER_PAGE,
+    MAX_ATTACHMENT_INV_PAGES_PER_REQUEST,
+};
+
+#[derive(Debug)]
+pub enum Error {
+    SqliteError(rusqlite::Error),
+    NotFoundError,
+    TooManyPagesRequested,
+}
+
+/// Presence of attachments in a single `(contract_id, page_index)` page,
+/// computed purely from the local index -- `bitmap[i]` is `true` when the
+/// attachment content backing `position_in_page == i` is locally available,
+/// without ever reading the attachment's bytes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AttachmentPageInventory {
+    pub contract_id: QualifiedContractIdentifier,
+    pub page_index: u32,
+    pub bitmap: Vec<bool>,
+    pub present_count: u32,
+    pub total_count: u32,
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Error {
+        Error::SqliteError(e)
+    }
+}
+
+const ATLASDB_SETUP: &'static [&'static str] = &[
+    r#"
+    CREATE TABLE attachments(
+        content_hash TEXT UNIQUE PRIMARY KEY,
+        content BLOB NOT NULL
+    );
+    "#,
+    r#"
+    CREATE TABLE attachment_instances(
+        content_hash TEXT NOT NULL,
+        page_index INTEGER NOT NULL,
+        position_in_page INTEGER NOT NULL,
+        block_height INTEGER NOT NULL,
+        consensus_hash TEXT NOT NULL,
+        block_header_hash TEXT NOT NULL,
+        metadata TEXT NOT NULL,
+        contract_id TEXT NOT NULL,
+        PRIMARY KEY(contract_id, page_index, position_in_page, consensus_hash, block_header_hash)
+    );
+    "#,
+    r#"
+    CREATE TABLE metadata_cache(
+        content_hash TEXT UNIQUE PRIMARY KEY,
+        decoded_metadata TEXT NOT NULL,
+        bns_name TEXT,
+        bns_namespace TEXT,
+        consensus_hash TEXT NOT NULL
+    );
+    "#,
+];
+
+/// The result of decoding an `AttachmentInstance::metadata` blob and, if it
+/// carries one, resolving the BNS name/namespace it names -- cached by
+/// `content_hash` so repeated inventory scans and name lookups for the same
+/// attachment don't redo the decode/regex work.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachedAttachmentMetadata {
+    pub decoded_metadata: String,
+    pub bns_name: Option<String>,
+    pub bns_namespace: Option<String>,
+}
+
+/// Local storage for Atlas: attachment blobs indexed by content hash, the
+/// on-chain `AttachmentInstance`s that reference them, and (as of later
+/// revisions) ancillary caches that avoid re-deriving work that's already
+/// been done once for a given `content_hash`.
+pub struct AtlasDB {
+    pub conn: Connection,
+    pub readwrite: bool,
+}
+
+impl AtlasDB {
+    fn instantiate(&mut self) -> Result<(), Error> {
+        let tx = self.conn.transaction()?;
+        for row in ATLASDB_SETUP {
+            tx.execute_batch(row)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn connect(path: &str, readwrite: bool) -> Result<AtlasDB, Error> {
+        let mut create_flag = false;
+        let open_flags = if path == ":memory:" {
+            create_flag = true;
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE
+        } else if std::fs::metadata(path).is_err() {
+            if !readwrite {
+                return Err(Error::NotFoundError);
+            }
+            create_flag = true;
+            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE
+        } else if readwrite {
+            OpenFlags::SQLITE_OPEN_READ_WRITE
+        } else {
+            OpenFlags::SQLITE_OPEN_READ_ONLY
+        };
+
+        let conn = Connection::open_with_flags(path, open_flags)?;
+        let mut db = AtlasDB { conn, readwrite };
+        if create_flag {
+            db.instantiate()?;
+        }
+        Ok(db)
+    }
+
+    pub fn insert_attachment(&self, attachment: &Attachment) -> Result<(), Error> {
+        let content_hash = to_hex(attachment.merkle_root().as_bytes());
+        self.conn.execute(
+            "INSERT OR REPLACE INTO attachments (content_hash, content) VALUES (?1, ?2)",
+            rusqlite::params![content_hash, attachment.content],
+        )?;
+        Ok(())
+    }
+
+    /// Records that `instance` was seen on chain. Re-announcements of the
+    /// same `(contract_id, page_index, position_in_page)` under a different
+    /// `(consensus_hash, block_header_hash)` -- e.g. because of a fork -- are
+    /// additional rows, not replacements, since the primary key includes
+    /// those columns; see `get_attachments_inventory` for why that matters.
+    pub fn insert_instantiated_attachment(&self, instance: &AttachmentInstance) -> Result<(), Error> {
+        self.conn.execute(
+            "INSERT OR REPLACE INTO attachment_instances
+             (content_hash, page_index, position_in_page, block_height, consensus_hash, block_header_hash, metadata, contract_id)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            rusqlite::params![
+                to_hex(instance.content_hash.as_bytes()),
+                instance.page_index,
+                instance.position_in_page,
+                instance.block_height as i64,
+                to_hex(instance.consensus_hash.as_bytes()),
+                to_hex(instance.block_header_hash.as_bytes()),
+                instance.metadata,
+                instance.contract_id.to_string(),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Stores `attachment` only if it both (a) actually hashes to
+    /// `instance.content_hash` -- otherwise nothing stops a caller from
+    /// persisting arbitrary bytes "on behalf of" an instance they have
+    /// nothing to do with, which would defeat content-hash-addressed storage
+    /// -- and (b) satisfies the `ContractPolicy` registered for
+    /// `instance.contract_id`. Returns `Ok(false)` (rather than an error) for
+    /// any of these cases, since none of them is exceptional -- the caller
+    /// should simply not treat the attachment as accepted.
+    pub fn insert_attachment_if_allowed(
+        &self,
+        atlas_config: &AtlasConfig,
+        instance: &AttachmentInstance,
+        attachment: &Attachment,
+    ) -> Result<bool, Error> {
+        if attachment.merkle_root() != instance.content_hash {
+            return Ok(false);
+        }
+        let policy = match atlas_config.policy_for(&instance.contract_id) {
+            Some(policy) => policy,
+            None => return Ok(false),
+        };
+        if !policy.accepts_content(&attachment.content) {
+            return Ok(false);
+        }
+        self.insert_attachment(attachment)?;
+        Ok(true)
+    }
+
+    pub fn find_attachment(&self, content_hash: &Hash160) -> Result<Option<Attachment>, Error> {
+        let content_hash = to_hex(content_hash.as_bytes());
+        let content: Option<Vec<u8>> = self
+            .conn
+            .query_row(
+                "SELECT content FROM attachments WHERE content_hash = ?1",
+                rusqlite::params![content_hash],
+                |row| row.get(0),
+            )
+            .ok();
+        Ok(content.map(Attachment::new))
+    }
+
+    /// Computes presence bitmaps for `pages` -- `(contract_id, page_index)`
+    /// pairs -- using only `attachment_instances`/`attachments` metadata, so
+    /// callers can diff local vs. remote inventory before scheduling any
+    /// attachment downloads. Mirrors reporting a block's transaction count
+    /// without decoding the transactions themselves.
+    pub fn get_attachments_inventory(
+        &self,
+        pages: &[(QualifiedContractIdentifier, u32)],
+    ) -> Result<Vec<AttachmentPageInventory>, Error> {
+        if pages.len() > MAX_ATTACHMENT_INV_PAGES_PER_REQUEST {
+            return Err(Error::TooManyPagesRequested);
+        }
+
+        let mut inventories = Vec::with_capacity(pages.len());
+        for (contract_id, page_index) in pages {
+            let mut bitmap = vec![false; ATTACHMENTS_PER_PAGE as usize];
+            let mut stmt = self.conn.prepare(
+                "SELECT i.position_in_page
+                 FROM attachment_instances i
+                 INNER JOIN attachments a ON a.content_hash = i.content_hash
+                 WHERE i.contract_id = ?1 AND i.page_index = ?2",
+            )?;
+            let rows = stmt.query_map(
+                rusqlite::params![contract_id.to_string(), page_index],
+                |row| row.get::<_, u32>(0),
+            )?;
+            // `attachment_instances`' primary key includes `consensus_hash`/
+            // `block_header_hash`, so the same `position_in_page` can
+            // legitimately have multiple rows (re-announced across blocks or
+            // forks). Set membership in the bitmap, rather than a running
+            // tally of rows seen, is what keeps `present_count` from
+            // exceeding `ATTACHMENTS_PER_PAGE`.
+            for row in rows {
+                let position_in_page = row?;
+                if let Some(slot) = bitmap.get_mut(position_in_page as usize) {
+                    *slot = true;
+                }
+            }
+            let present_count = bitmap.iter().filter(|present| **present).count() as u32;
+
+            let total_count: u32 = self.conn.query_row(
+                "SELECT COUNT(DISTINCT position_in_page) FROM attachment_instances WHERE contract_id = ?1 AND page_index = ?2",
+                rusqlite::params![contract_id.to_string(), page_index],
+                |row| row.get(0),
+            )?;
+
+            inventories.push(AttachmentPageInventory {
+                contract_id: contract_id.clone(),
+                page_index: *page_index,
+                bitmap,
+                present_count,
+                total_count,
+            });
+        }
+        Ok(inventories)
+    }
+
+    /// Returns the cached decode/BNS-resolution for `content_hash`, if one has
+    /// already been computed, without touching `decode_metadata`/regex logic.
+    pub fn get_cached_metadata(
+        &self,
+        content_hash: &Hash160,
+    ) -> Result<Option<CachedAttachmentMetadata>, Error> {
+        let content_hash = to_hex(content_hash.as_bytes());
+        self.conn
+            .query_row(
+                "SELECT decoded_metadata, bns_name, bns_namespace FROM metadata_cache WHERE content_hash = ?1",
+                rusqlite::params![content_hash],
+                |row| {
+                    Ok(CachedAttachmentMetadata {
+                        decoded_metadata: row.get(0)?,
+                        bns_name: row.get(1)?,
+                        bns_namespace: row.get(2)?,
+                    })
+                },
+            )
+            .optional()
+            .map_err(Error::from)
+    }
+
+    /// Decodes `instance.metadata` and resolves any BNS name/namespace it
+    /// carries, populating the cache the first time this `content_hash` is
+    /// seen and simply returning the cached row on every subsequent call.
+    pub fn get_or_decode_metadata(
+        &self,
+        instance: &AttachmentInstance,
+    ) -> Result<CachedAttachmentMetadata, Error> {
+        if let Some(cached) = self.get_cached_metadata(&instance.content_hash)? {
+            return Ok(cached);
+        }
+
+        let decoded_metadata = decode_metadata(&instance.metadata)?;
+        let (bns_name, bns_namespace) = resolve_bns_name(&decoded_metadata);
+
+        self.conn.execute(
+            "INSERT OR REPLACE INTO metadata_cache (content_hash, decoded_metadata, bns_name, bns_namespace, consensus_hash) VALUES (?1, ?2, ?3, ?4, ?5)",
+            rusqlite::params![
+                to_hex(instance.content_hash.as_bytes()),
+                decoded_metadata,
+                bns_name,
+                bns_namespace,
+                to_hex(instance.consensus_hash.as_bytes()),
+            ],
+        )?;
+
+        Ok(CachedAttachmentMetadata {
+            decoded_metadata,
+            bns_name,
+            bns_namespace,
+        })
+    }
+
+    /// Drops every cache entry anchored to `stale_consensus_hash`. Call this
+    /// when a reorg orphans the block that consensus hash belonged to, so a
+    /// later lookup re-decodes against the new canonical chain instead of
+    /// continuing to serve a result anchored to an abandoned fork.
+    pub fn invalidate_cache_for_reorg(&self, stale_consensus_hash: &ConsensusHash) -> Result<(), Error> {
+        self.conn.execute(
+            "DELETE FROM metadata_cache WHERE consensus_hash = ?1",
+            rusqlite::params![to_hex(stale_consensus_hash.as_bytes())],
+        )?;
+        Ok(())
+    }
+
+    /// Delegates to `chainstate`'s `OnchainInventoryLookup` implementation to
+    /// produce a MARF proof binding `contract_id`'s attachment entry to its
+    /// `content_hash` at `at_block`. Kept on `AtlasDB` (rather than requiring
+    /// every caller to reach into chainstate directly) so the rest of Atlas
+    /// has one place to ask for proof data, the same way it has one place to
+    /// ask for cached attachment content.
+    pub fn get_attachment_proof(
+        &self,
+        chainstate: &dyn OnchainInventoryLookup,
+        contract_id: &QualifiedContractIdentifier,
+        page_index: u32,
+        position_in_page: u32,
+        at_block: &StacksBlockId,
+    ) -> Result<AttachmentProof, Error> {
+        chainstate
+            .get_attachment_proof(contract_id, page_index, position_in_page, at_block)
+            .map_err(|_| Error::NotFoundError)
+    }
+}
+
+/// Reverses the hex-encode/`consensus_serialize` done in
+/// `AttachmentInstance::try_new_from_value` back into a displayable Clarity
+/// value, so it only has to happen once per `content_hash`.
+pub(crate) fn decode_metadata(metadata_hex: &str) -> Result<String, Error> {
+    let bytes = hex_bytes(metadata_hex).map_err(|_| Error::NotFoundError)?;
+    let value = Value::consensus_deserialize(&mut &bytes[..]).map_err(|_| Error::NotFoundError)?;
+    Ok(format!("{}", value))
+}
+
+/// Looks for a `BNS_NAME_REGEX` match in `decoded_metadata` and, if found,
+/// pulls the name and namespace out of their own capture groups. This has to
+/// read the groups directly rather than re-splitting the full match on `.` --
+/// `BNS_NAME_REGEX` has an optional third dotted (subdomain) segment, and
+/// splitting the whole match would let that segment's leading `.` get
+/// swallowed into the namespace instead of being its own component.
+pub(crate) fn resolve_bns_name(decoded_metadata: &str) -> (Option<String>, Option<String>) {
+    let regex = match Regex::new(&*BNS_NAME_REGEX) {
+        Ok(regex) => regex,
+        Err(_) => return (None, None),
+    };
+    let captures = match regex.captures(decoded_metadata) {
+        Some(captures) => captures,
+        None => return (None, None),
+    };
+    let name = captures.get(1).map(|m| m.as_str().to_string());
+    let namespace = captures.get(2).map(|m| m.as_str().to_string());
+    (name, namespace)
+}
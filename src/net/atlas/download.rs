@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+
+use super::db::{AtlasDB, AttachmentPageInventory, Error as DBError};
+use super::merkle::MerkleTree;
+use super::{Attachment, ATTACHMENTS_CHUNK_SIZE};
+use util::hash::Hash160;
+use vm::types::QualifiedContractIdentifier;
+
+/// Coordinates fetching an `Attachment`'s content one chunk at a time and
+/// verifying each chunk against the attachment's known Merkle root
+/// (`AttachmentInstance::content_hash`) before it is accepted, so a requester
+/// never has to hold (or trust) the whole blob to validate a piece of it.
+/// Serving a piece (`request_chunk`) works from the whole attachment.
+pub struct AttachmentsDownloader {
+    /// Root hash -> chunks collected so far for that attachment.
+    inflight: HashMap<Hash160, PartialAttachment>,
+}
+
+struct PartialAttachment {
+    total_chunks: usize,
+    chunks: HashMap<usize, Vec<u8>>,
+}
+
+impl AttachmentsDownloader {
+    pub fn new() -> AttachmentsDownloader {
+        AttachmentsDownloader {
+            inflight: HashMap::new(),
+        }
+    }
+
+    /// Number of chunks expected for an attachment of `content_len` bytes.
+    pub fn chunk_count(content_len: usize) -> usize {
+        if content_len == 0 {
+            0
+        } else {
+            (content_len + ATTACHMENTS_CHUNK_SIZE - 1) / ATTACHMENTS_CHUNK_SIZE
+        }
+    }
+
+    /// Ingests a single chunk fetched from a peer. The chunk is rejected if it
+    /// doesn't verify against `root` along `path` (see `MerkleTree::verify_path`).
+    /// Once every chunk of `total_chunks` under `root` has been seen and
+    /// verified, the attachment is reassembled and returned.
+    pub fn ingest_chunk(
+        &mut self,
+        root: &Hash160,
+        total_chunks: usize,
+        index: usize,
+        chunk: Vec<u8>,
+        path: &[Hash160],
+    ) -> Result<Option<Attachment>, ()> {
+        if index >= total_chunks {
+            return Err(());
+        }
+        if !MerkleTree::<Hash160>::verify_path(root, &chunk, index, path) {
+            return Err(());
+        }
+
+        let partial = self.inflight.entry(root.clone()).or_insert_with(|| PartialAttachment {
+            total_chunks,
+            chunks: HashMap::new(),
+        });
+        partial.chunks.insert(index, chunk);
+
+        if partial.chunks.len() < partial.total_chunks {
+            return Ok(None);
+        }
+
+        let partial = self
+            .inflight
+            .remove(root)
+            .expect("just inserted into this map's entry for `root`");
+        Self::reassemble(partial).map(Some)
+    }
+
+    /// Abandons any in-flight chunks collected so far for `root`, e.g. because
+    /// the peer serving them turned out to be unresponsive or malicious.
+    pub fn drop_inflight(&mut self, root: &Hash160) {
+        self.inflight.remove(root);
+    }
+
+    /// The serving side of chunked retrieval: given the whole `attachment`
+    /// (held locally by whoever answers the request) and the `index` a peer
+    /// asked for, produces the chunk plus the Merkle path a requester needs
+    /// to verify it against `attachment.merkle_root()` via `ingest_chunk`.
+    pub fn request_chunk(attachment: &Attachment, index: usize) -> Option<(Vec<u8>, Vec<Hash160>)> {
+        attachment.chunk_with_path(index)
+    }
+
+    /// Recovers the hash of a zero-padded leaf at `index` from the Merkle
+    /// tree's root map, for the case where `index` falls past the attachment's
+    /// real chunk count (i.e. it's padding added to round the leaf count up to
+    /// a power of two). There is no chunk to request for such an index -- the
+    /// hash is already known from how the tree was built -- so recovery reads
+    /// it back from the root map rather than re-deriving it from data that
+    /// never existed. Returns `None` for a real (non-padded) index.
+    pub fn recover_padded_leaf(attachment: &Attachment, index: usize) -> Option<Hash160> {
+        let chunks = attachment.chunks();
+        if chunks.is_empty() || index < chunks.len() {
+            return None;
+        }
+        let tree = MerkleTree::<Hash160>::new(chunks);
+        tree.subtree_hash(0, index).cloned()
+    }
+
+    /// Reports which attachments are already held locally for `pages`, without
+    /// reading any attachment bytes, so sync logic can diff local vs. remote
+    /// inventory and only schedule chunk downloads for what's actually
+    /// missing. Bounded by `MAX_ATTACHMENT_INV_PAGES_PER_REQUEST` (enforced by
+    /// `AtlasDB::get_attachments_inventory`).
+    pub fn get_attachments_inventory(
+        &self,
+        atlas_db: &AtlasDB,
+        pages: &[(QualifiedContractIdentifier, u32)],
+    ) -> Result<Vec<AttachmentPageInventory>, DBError> {
+        atlas_db.get_attachments_inventory(pages)
+    }
+
+    fn reassemble(partial: PartialAttachment) -> Result<Attachment, ()> {
+        let mut content = Vec::new();
+        for i in 0..partial.total_chunks {
+            let mut chunk = partial
+                .chunks
+                .get(&i)
+                .cloned()
+                .expect("all chunks present: checked by caller");
+            if i + 1 == partial.total_chunks {
+                // The final chunk is length-prefixed (see `Attachment::chunks()`)
+                // so that zero-padding introduced to round the leaf count up to
+                // a power of two can never be mistaken for real content.
+                if chunk.len() < 4 {
+                    return Err(());
+                }
+                let declared_len =
+                    u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]) as usize;
+                chunk.drain(0..4);
+                if declared_len > chunk.len() {
+                    return Err(());
+                }
+                chunk.truncate(declared_len);
+            }
+            content.extend_from_slice(&chunk);
+        }
+        Ok(Attachment::new(content))
+    }
+}
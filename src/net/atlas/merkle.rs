@@ -0,0 +1,97 @@
+use util::hash::MerkleHashFunc;
+
+/// A binary Merkle tree over an `Attachment`'s chunks, used so a peer can
+/// serve and verify individual chunks of a large attachment instead of the
+/// whole blob. Leaves are `H::from_data(chunk)` (the caller is responsible for
+/// length-prefixing the final, possibly short, chunk -- see
+/// `Attachment::chunks()`); the leaf count is padded up to the next power of
+/// two with `H::empty()` leaves so the tree is always a perfect binary tree.
+/// Internal nodes are `H::from_data(left.as_bytes() || right.as_bytes())`.
+///
+/// Every level is kept around (the "root map"), not just the leaves and the
+/// root, so that a padded subtree's hash can be looked up directly during
+/// recovery instead of being recomputed from (nonexistent) zero chunks.
+pub struct MerkleTree<H: MerkleHashFunc + Clone + PartialEq> {
+    levels: Vec<Vec<H>>,
+}
+
+impl<H: MerkleHashFunc + Clone + PartialEq> MerkleTree<H> {
+    /// Builds a tree over `chunks`. Panics if `chunks` is empty -- callers
+    /// should special-case empty attachments to `H::empty()` instead of
+    /// constructing a tree (see `Attachment::merkle_root()`).
+    pub fn new(chunks: Vec<Vec<u8>>) -> MerkleTree<H> {
+        assert!(!chunks.is_empty(), "MerkleTree needs at least one chunk");
+
+        let mut leaves: Vec<H> = chunks.iter().map(|chunk| H::from_data(chunk)).collect();
+
+        let padded_len = leaves.len().next_power_of_two();
+        leaves.resize(padded_len, H::empty());
+
+        let mut levels = vec![leaves];
+        while levels.last().expect("levels is never empty").len() > 1 {
+            let next_level = levels
+                .last()
+                .expect("levels is never empty")
+                .chunks(2)
+                .map(|pair| MerkleTree::hash_node(&pair[0], &pair[1]))
+                .collect();
+            levels.push(next_level);
+        }
+
+        MerkleTree { levels }
+    }
+
+    fn hash_node(left: &H, right: &H) -> H {
+        let mut buf = Vec::with_capacity(left.as_bytes().len() + right.as_bytes().len());
+        buf.extend_from_slice(left.as_bytes());
+        buf.extend_from_slice(right.as_bytes());
+        H::from_data(&buf)
+    }
+
+    pub fn root(&self) -> H {
+        self.levels.last().expect("levels is never empty")[0].clone()
+    }
+
+    pub fn leaf_count(&self) -> usize {
+        self.levels[0].len()
+    }
+
+    /// Sibling hashes for the leaf at `index`, ordered from the leaf's
+    /// immediate sibling up to (but not including) the root.
+    pub fn path(&self, index: usize) -> Option<Vec<H>> {
+        if index >= self.leaf_count() {
+            return None;
+        }
+        let mut path = Vec::with_capacity(self.levels.len().saturating_sub(1));
+        let mut idx = index;
+        for level in &self.levels[..self.levels.len() - 1] {
+            path.push(level[idx ^ 1].clone());
+            idx /= 2;
+        }
+        Some(path)
+    }
+
+    /// Recomputes the root from `chunk` at `index` along `path` and checks it
+    /// against `root`, without needing the rest of the tree in memory.
+    pub fn verify_path(root: &H, chunk: &[u8], index: usize, path: &[H]) -> bool {
+        let mut hash = H::from_data(chunk);
+        let mut idx = index;
+        for sibling in path {
+            hash = if idx % 2 == 0 {
+                MerkleTree::hash_node(&hash, sibling)
+            } else {
+                MerkleTree::hash_node(sibling, &hash)
+            };
+            idx /= 2;
+        }
+        &hash == root
+    }
+
+    /// Looks up the recorded hash of the subtree rooted at `index` within
+    /// `level` (`level` 0 is the leaves). Used during recovery to reconstruct
+    /// a missing padded subtree from the root map rather than re-deriving it
+    /// from chunks that were never real data in the first place.
+    pub fn subtree_hash(&self, level: usize, index: usize) -> Option<&H> {
+        self.levels.get(level).and_then(|l| l.get(index))
+    }
+}
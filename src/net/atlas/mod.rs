@@ -1,10 +1,11 @@
 pub mod db;
 pub mod download;
+pub mod merkle;
 pub mod onchain;
 
-pub use self::db::AtlasDB;
+pub use self::db::{AtlasDB, AttachmentPageInventory, CachedAttachmentMetadata};
 pub use self::download::AttachmentsDownloader;
-pub use self::onchain::OnchainInventoryLookup;
+pub use self::onchain::{verify_attachment_proof, AttachmentProof, OnchainInventoryLookup};
 
 use chainstate::stacks::boot::boot_code_id;
 use chainstate::stacks::{StacksBlockHeader, StacksBlockId};
@@ -12,12 +13,13 @@ use chainstate::stacks::{StacksBlockHeader, StacksBlockId};
 use chainstate::burn::db::sortdb::SortitionDB;
 use chainstate::burn::{BlockHeaderHash, ConsensusHash};
 use net::StacksMessageCodec;
-use util::hash::{to_hex, Hash160, MerkleHashFunc};
+use util::hash::{to_hex, Hash160};
 use vm::types::{QualifiedContractIdentifier, SequenceData, TupleData, Value};
 
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::hash::{Hash, Hasher};
+use std::sync::Arc;
 
 pub const BNS_NAMESPACE_MIN_LEN: usize = 1;
 pub const BNS_NAMESPACE_MAX_LEN: usize = 19;
@@ -25,29 +27,99 @@ pub const BNS_NAME_MIN_LEN: usize = 1;
 pub const BNS_NAME_MAX_LEN: usize = 16;
 pub const MAX_ATTACHMENT_INV_PAGES_PER_REQUEST: usize = 8;
 
+/// Number of `position_in_page` slots in a single attachments page, as laid
+/// out by the Atlas-managed contracts' data maps.
+pub const ATTACHMENTS_PER_PAGE: u32 = 8;
+
+/// Size, in bytes, of each leaf used when an `Attachment`'s content is split up
+/// for chunked retrieval and Merkle verification. Content at or below this size
+/// still goes through the same machinery and simply produces a single-leaf tree.
+pub const ATTACHMENTS_CHUNK_SIZE: usize = 256 * 1024;
+
 lazy_static! {
+    // The name/namespace each get their own capturing group wrapped around
+    // the *whole* repeated run (`(group-of-chars{min,max})`), not a
+    // capturing group that itself repeats (`(char){min,max}`, which in
+    // standard regex semantics only ever captures the last iteration). The
+    // first segment is the BNS name, the second is the namespace -- e.g.
+    // `satoshi.id` is name `satoshi` in namespace `id` -- so the name
+    // segment is bounded by `BNS_NAME_MIN_LEN/MAX_LEN` and the namespace
+    // segment by `BNS_NAMESPACE_MIN_LEN/MAX_LEN`. The optional subdomain
+    // segment isn't extracted anywhere, so it stays non-capturing.
     pub static ref BNS_NAME_REGEX: String = format!(
-        r#"([a-z0-9]|[-_]){{{},{}}}\.([a-z0-9]|[-_]){{{},{}}}(\.([a-z0-9]|[-_]){{{},{}}})?"#,
-        BNS_NAMESPACE_MIN_LEN, BNS_NAMESPACE_MAX_LEN, BNS_NAME_MIN_LEN, BNS_NAME_MAX_LEN, 1, 128
+        r#"((?:[a-z0-9]|[-_]){{{},{}}})\.((?:[a-z0-9]|[-_]){{{},{}}})(?:\.(?:[a-z0-9]|[-_]){{{},{}}})?"#,
+        BNS_NAME_MIN_LEN, BNS_NAME_MAX_LEN, BNS_NAMESPACE_MIN_LEN, BNS_NAMESPACE_MAX_LEN, 1, 128
     );
 }
 
-pub struct AtlasConfig {
-    pub contracts: HashSet<QualifiedContractIdentifier>,
+/// Per-contract rules Atlas applies to attachments registered by a given
+/// contract, so that e.g. the BNS contract can keep the standard 1 MiB cap
+/// while a separate app contract permits larger blobs or restricts itself to
+/// a specific format.
+#[derive(Clone)]
+pub struct ContractPolicy {
+    /// Upper bound, in bytes, on attachment content accepted for this contract.
     pub attachments_max_size: u32,
+    /// When set, accepted content must start with one of these byte strings
+    /// (e.g. magic bytes identifying a file format).
+    pub allowed_content_prefixes: Option<Vec<Vec<u8>>>,
+    /// When set, run against the raw on-chain attachment tuple before an
+    /// `AttachmentInstance` is accepted for this contract.
+    pub validate_instance: Option<Arc<dyn Fn(&Value) -> bool + Send + Sync>>,
 }
 
-impl AtlasConfig {
-    pub fn default() -> AtlasConfig {
-        let mut contracts = HashSet::new();
-        contracts.insert(boot_code_id("bns"));
-        AtlasConfig {
-            contracts,
+impl ContractPolicy {
+    pub fn standard() -> ContractPolicy {
+        ContractPolicy {
             attachments_max_size: 1_048_576,
+            allowed_content_prefixes: None,
+            validate_instance: None,
+        }
+    }
+
+    /// Checks `content` against `attachments_max_size` and
+    /// `allowed_content_prefixes`. Used once an attachment's bytes are in
+    /// hand (e.g. after a download completes), as opposed to
+    /// `validate_instance`, which only ever sees the on-chain descriptor.
+    pub fn accepts_content(&self, content: &[u8]) -> bool {
+        if content.len() as u32 > self.attachments_max_size {
+            return false;
+        }
+        match &self.allowed_content_prefixes {
+            Some(prefixes) => prefixes.iter().any(|prefix| content.starts_with(prefix)),
+            None => true,
         }
     }
 }
 
+pub struct AtlasConfig {
+    pub mainnet: bool,
+    pub contracts: HashMap<QualifiedContractIdentifier, ContractPolicy>,
+}
+
+impl AtlasConfig {
+    /// Seeds network-appropriate defaults: the BNS contract, registered under
+    /// the standard policy, for the given network.
+    pub fn new(mainnet: bool) -> AtlasConfig {
+        let mut contracts = HashMap::new();
+        contracts.insert(boot_code_id("bns", mainnet), ContractPolicy::standard());
+        AtlasConfig { mainnet, contracts }
+    }
+
+    /// Registers (or replaces) the policy Atlas applies to `contract_id`.
+    pub fn register_contract(&mut self, contract_id: QualifiedContractIdentifier, policy: ContractPolicy) {
+        self.contracts.insert(contract_id, policy);
+    }
+
+    pub fn is_contract_managed(&self, contract_id: &QualifiedContractIdentifier) -> bool {
+        self.contracts.contains_key(contract_id)
+    }
+
+    pub fn policy_for(&self, contract_id: &QualifiedContractIdentifier) -> Option<&ContractPolicy> {
+        self.contracts.get(contract_id)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq, Hash)]
 pub struct Attachment {
     pub content: Vec<u8>,
@@ -58,8 +130,58 @@ impl Attachment {
         Attachment { content }
     }
 
-    pub fn hash(&self) -> Hash160 {
-        Hash160::from_data(&self.content)
+    /// Splits `content` into fixed-size `ATTACHMENTS_CHUNK_SIZE` chunks. The final
+    /// chunk (which may be shorter than the others) is length-prefixed with its
+    /// own byte length so that, once it is padded out to a power-of-two leaf
+    /// count with zero leaves, trailing zero padding can never be mistaken for a
+    /// genuinely shorter final chunk when a path is verified.
+    pub fn chunks(&self) -> Vec<Vec<u8>> {
+        if self.content.is_empty() {
+            return vec![];
+        }
+        let last_index = (self.content.len() - 1) / ATTACHMENTS_CHUNK_SIZE;
+        self.content
+            .chunks(ATTACHMENTS_CHUNK_SIZE)
+            .enumerate()
+            .map(|(i, chunk)| {
+                if i == last_index {
+                    let mut prefixed = (chunk.len() as u32).to_be_bytes().to_vec();
+                    prefixed.extend_from_slice(chunk);
+                    prefixed
+                } else {
+                    chunk.to_vec()
+                }
+            })
+            .collect()
+    }
+
+    /// Root of the Merkle tree built over `chunks()`. This is the canonical
+    /// content-addressing hash recorded as `AttachmentInstance::content_hash`
+    /// -- there is no separate whole-blob hash, so a single-chunk attachment's
+    /// root (which is still length-prefixed, since it's also the final chunk)
+    /// is exactly as authoritative as a multi-chunk one's. Empty content maps
+    /// to `Hash160::empty()`, matching the untouched `content_hash` case in
+    /// `AttachmentInstance::try_new_from_value`.
+    pub fn merkle_root(&self) -> Hash160 {
+        let chunks = self.chunks();
+        if chunks.is_empty() {
+            return Hash160::empty();
+        }
+        merkle::MerkleTree::<Hash160>::new(chunks).root()
+    }
+
+    /// Returns the chunk at `index` together with the Merkle path proving it
+    /// belongs under `merkle_root()`, i.e. what a peer holding this attachment
+    /// serves in response to a request for a single chunk by index. Returns
+    /// `None` for empty content (nothing to serve) or an out-of-range index.
+    pub fn chunk_with_path(&self, index: usize) -> Option<(Vec<u8>, Vec<Hash160>)> {
+        let chunks = self.chunks();
+        if chunks.is_empty() {
+            return None;
+        }
+        let chunk = chunks.get(index)?.clone();
+        let path = merkle::MerkleTree::<Hash160>::new(chunks).path(index)?;
+        Some((chunk, path))
     }
 }
 
@@ -86,7 +208,17 @@ impl AttachmentInstance {
         consensus_hash: &ConsensusHash,
         block_header_hash: BlockHeaderHash,
         block_height: u64,
+        atlas_config: &AtlasConfig,
     ) -> Result<AttachmentInstance, ()> {
+        let policy = match atlas_config.policy_for(contract_id) {
+            Some(policy) => policy,
+            None => return Err(()),
+        };
+        if let Some(validate_instance) = &policy.validate_instance {
+            if !validate_instance(value) {
+                return Err(());
+            }
+        }
         if let Value::Tuple(ref attachment) = value {
             if let Ok(Value::Tuple(ref attachment_data)) = attachment.get("attachment") {
                 match (
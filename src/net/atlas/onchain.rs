@@ -0,0 +1,107 @@
+use chainstate::stacks::index::proofs::TrieMerkleProof;
+use chainstate::stacks::index::TrieHash;
+use chainstate::stacks::{StacksBlockHeader, StacksBlockId};
+use net::StacksMessageCodec;
+use util::hash::{to_hex, Hash160};
+use vm::database::ClarityDatabase;
+use vm::types::{QualifiedContractIdentifier, TupleData, Value};
+
+/// A MARF Merkle proof binding `(contract_id, page_index, position_in_page)` to
+/// an `AttachmentInstance::content_hash` as of a specific block, bundled with
+/// the header needed to anchor it. This is everything a verifier needs in
+/// order to check the binding without holding full chainstate -- only a
+/// header it already trusts (e.g. pinned by a prior full validation, or a
+/// checkpoint it was configured with).
+pub struct AttachmentProof {
+    pub marf_proof: TrieMerkleProof<TrieHash>,
+    pub header: StacksBlockHeader,
+}
+
+/// Looks up attachment-related chain state on behalf of Atlas, so that
+/// `AtlasDB` doesn't need to depend directly on chainstate/MARF internals.
+/// Implemented by whatever component owns chainstate access (a node's
+/// `StacksChainState`, or a light-client shim talking to a trusted peer).
+pub trait OnchainInventoryLookup {
+    /// Raw bytes backing the attachments-availability bitmap for `page_index`
+    /// as of `at_block`, used to drive inventory sync.
+    fn get_attachments_available_at_page_index(
+        &self,
+        page_index: u32,
+        at_block: &StacksBlockId,
+    ) -> Result<Vec<u8>, ()>;
+
+    /// Produces a MARF proof that `contract_id`'s attachment data map entry
+    /// for `(page_index, position_in_page)` resolves to its recorded
+    /// `content_hash` as of `at_block`.
+    fn get_attachment_proof(
+        &self,
+        contract_id: &QualifiedContractIdentifier,
+        page_index: u32,
+        position_in_page: u32,
+        at_block: &StacksBlockId,
+    ) -> Result<AttachmentProof, ()>;
+}
+
+/// Recomputes the MARF path for `(contract_id, page_index, position_in_page)`
+/// under `proof.marf_proof` and checks that it both resolves to
+/// `expected_content_hash` and is anchored to `trusted_header`'s state root --
+/// so a wallet or indexer that fetched an attachment from an untrusted peer
+/// can confirm it actually matches what was committed on-chain.
+pub fn verify_attachment_proof(
+    proof: &AttachmentProof,
+    contract_id: &QualifiedContractIdentifier,
+    page_index: u32,
+    position_in_page: u32,
+    expected_content_hash: &Hash160,
+    trusted_header: &StacksBlockHeader,
+) -> bool {
+    if proof.header.block_hash() != trusted_header.block_hash() {
+        return false;
+    }
+
+    let path = attachment_marf_key(contract_id, page_index, position_in_page);
+    let value = expected_content_hash.as_bytes().to_vec();
+
+    TrieMerkleProof::verify(
+        &proof.marf_proof,
+        &path,
+        &value,
+        &proof.header.state_index_root,
+    )
+}
+
+/// The Clarity data-map name the attachments contracts Atlas manages use to
+/// record attachment instances, keyed by `(page-index, position-in-page)`.
+const ATTACHMENTS_MAP_NAME: &'static str = "attachments";
+
+/// The MARF key under which an attachment's `content_hash` is stored in
+/// `contract_id`'s data space. This has to be derived exactly the way
+/// `ClarityDatabase` derives it for a real data-map entry -- the key tuple is
+/// serialized and hex-encoded the same way `AttachmentInstance::metadata` is
+/// (see `try_new_from_value`), then handed to the same
+/// `make_key_for_data_map_entry` helper the VM itself uses to store the map
+/// entry, so the MARF path we check the proof against is the one actually
+/// committed on-chain rather than an ad hoc format.
+pub(crate) fn attachment_marf_key(
+    contract_id: &QualifiedContractIdentifier,
+    page_index: u32,
+    position_in_page: u32,
+) -> String {
+    let key_tuple = Value::from(
+        TupleData::from_data(vec![
+            ("page-index".into(), Value::UInt(page_index as u128)),
+            ("position-in-page".into(), Value::UInt(position_in_page as u128)),
+        ])
+        .expect("FATAL: failed to construct attachment map key tuple"),
+    );
+    let mut serialized = vec![];
+    key_tuple
+        .consensus_serialize(&mut serialized)
+        .expect("FATAL: failed to serialize attachment map key tuple");
+
+    ClarityDatabase::make_key_for_data_map_entry(
+        contract_id,
+        ATTACHMENTS_MAP_NAME,
+        &to_hex(&serialized[..]),
+    )
+}
@@ -0,0 +1,270 @@
+use super::db::{decode_metadata, resolve_bns_name, AtlasDB};
+use super::download::AttachmentsDownloader;
+use super::merkle::MerkleTree;
+use super::onchain::attachment_marf_key;
+use super::{Attachment, AtlasConfig, AttachmentInstance, ContractPolicy, ATTACHMENTS_CHUNK_SIZE};
+use chainstate::burn::{BlockHeaderHash, ConsensusHash};
+use chainstate::stacks::boot::boot_code_id;
+use net::StacksMessageCodec;
+use util::hash::{to_hex, Hash160};
+use vm::types::{QualifiedContractIdentifier, Value};
+
+#[test]
+fn empty_attachment_merkle_root_is_empty_hash() {
+    let attachment = Attachment::new(vec![]);
+    assert_eq!(attachment.chunks().len(), 0);
+    assert_eq!(attachment.merkle_root(), Hash160::empty());
+}
+
+#[test]
+fn single_chunk_root_is_its_length_prefixed_leaf_hash() {
+    let attachment = Attachment::new(b"hello world".to_vec());
+    let chunks = attachment.chunks();
+    assert_eq!(chunks.len(), 1);
+
+    let expected_leaf = Hash160::from_data(&chunks[0]);
+    assert_eq!(attachment.merkle_root(), expected_leaf);
+}
+
+#[test]
+fn length_prefix_prevents_padding_collisions() {
+    // Two final chunks whose raw bytes would collide once the shorter one is
+    // conceptually "zero padded" out to the longer one's length -- the
+    // length prefix added by `Attachment::chunks()` must keep their hashes
+    // (and therefore their Merkle roots) distinct.
+    let short = Attachment::new(vec![0x41, 0x42]);
+    let long = Attachment::new(vec![0x41, 0x42, 0x00, 0x00]);
+
+    assert_ne!(short.chunks()[0], long.chunks()[0]);
+    assert_ne!(short.merkle_root(), long.merkle_root());
+}
+
+#[test]
+fn chunk_with_path_verifies_against_merkle_root() {
+    let content: Vec<u8> = (0..(ATTACHMENTS_CHUNK_SIZE * 3 + 17))
+        .map(|i| (i % 256) as u8)
+        .collect();
+    let attachment = Attachment::new(content);
+    let root = attachment.merkle_root();
+    let chunk_count = attachment.chunks().len();
+    assert_eq!(chunk_count, 4);
+
+    for index in 0..chunk_count {
+        let (chunk, path) = attachment
+            .chunk_with_path(index)
+            .expect("expected a chunk at this index");
+        assert!(MerkleTree::<Hash160>::verify_path(&root, &chunk, index, &path));
+    }
+
+    // Tampering with the chunk must invalidate the path.
+    let (mut chunk, path) = attachment.chunk_with_path(0).unwrap();
+    chunk.push(0xff);
+    assert!(!MerkleTree::<Hash160>::verify_path(&root, &chunk, 0, &path));
+}
+
+#[test]
+fn downloader_reassembles_from_served_chunks() {
+    let content: Vec<u8> = (0..(ATTACHMENTS_CHUNK_SIZE * 2 + 5))
+        .map(|i| (i % 251) as u8)
+        .collect();
+    let attachment = Attachment::new(content.clone());
+    let root = attachment.merkle_root();
+    let total_chunks = attachment.chunks().len();
+
+    let mut downloader = AttachmentsDownloader::new();
+    let mut reassembled = None;
+    for index in 0..total_chunks {
+        let (chunk, path) = AttachmentsDownloader::request_chunk(&attachment, index).unwrap();
+        reassembled = downloader
+            .ingest_chunk(&root, total_chunks, index, chunk, &path)
+            .expect("chunk should verify");
+    }
+
+    assert_eq!(reassembled.expect("attachment fully reassembled").content, content);
+}
+
+#[test]
+fn contract_policy_enforces_size_cap_and_prefix_allowlist() {
+    let mut policy = ContractPolicy::standard();
+    policy.attachments_max_size = 4;
+    assert!(policy.accepts_content(&[1, 2, 3, 4]));
+    assert!(!policy.accepts_content(&[1, 2, 3, 4, 5]));
+
+    policy.allowed_content_prefixes = Some(vec![vec![0x89, b'P', b'N', b'G']]);
+    assert!(!policy.accepts_content(&[1, 2, 3]));
+}
+
+#[test]
+fn atlas_config_only_manages_registered_contracts() {
+    let bns = boot_code_id("bns", true);
+    let unregistered = boot_code_id("pox", true);
+
+    let config = AtlasConfig::new(true);
+    assert!(config.is_contract_managed(&bns));
+    assert!(!config.is_contract_managed(&unregistered));
+    assert!(config.policy_for(&unregistered).is_none());
+}
+
+fn test_instance(contract_id: QualifiedContractIdentifier, content_hash: Hash160) -> AttachmentInstance {
+    AttachmentInstance {
+        content_hash,
+        page_index: 0,
+        position_in_page: 0,
+        block_height: 1,
+        consensus_hash: ConsensusHash([0u8; 20]),
+        block_header_hash: BlockHeaderHash([0u8; 32]),
+        metadata: String::new(),
+        contract_id,
+    }
+}
+
+#[test]
+fn insert_attachment_if_allowed_rejects_content_hash_mismatch() {
+    let atlas_config = AtlasConfig::new(true);
+    let bns = boot_code_id("bns", true);
+    let db = AtlasDB::connect(":memory:", true).expect("in-memory AtlasDB should connect");
+
+    let attachment = Attachment::new(b"real content".to_vec());
+    let wrong_instance = test_instance(bns.clone(), Hash160::empty());
+
+    let accepted = db
+        .insert_attachment_if_allowed(&atlas_config, &wrong_instance, &attachment)
+        .expect("should not error");
+    assert!(!accepted, "content not matching the instance's content_hash must be rejected");
+    assert!(db.find_attachment(&attachment.merkle_root()).unwrap().is_none());
+
+    let matching_instance = test_instance(bns, attachment.merkle_root());
+    let accepted = db
+        .insert_attachment_if_allowed(&atlas_config, &matching_instance, &attachment)
+        .expect("should not error");
+    assert!(accepted);
+    assert!(db.find_attachment(&attachment.merkle_root()).unwrap().is_some());
+}
+
+#[test]
+fn resolve_bns_name_keeps_subdomain_out_of_namespace() {
+    // `name.namespace` with no subdomain.
+    assert_eq!(
+        resolve_bns_name("satoshi.id"),
+        (Some("satoshi".to_string()), Some("id".to_string()))
+    );
+
+    // `name.namespace.subdomain` -- the third segment must not leak into
+    // `namespace` the way splitting the whole match on '.' would cause.
+    let (name, namespace) = resolve_bns_name("alice.id.app");
+    assert_eq!(name, Some("alice".to_string()));
+    assert_eq!(namespace, Some("id".to_string()));
+}
+
+#[test]
+fn resolve_bns_name_returns_none_when_no_match() {
+    assert_eq!(resolve_bns_name("not a bns name"), (None, None));
+}
+
+#[test]
+fn decode_metadata_round_trips_a_consensus_serialized_value() {
+    let value = Value::UInt(42);
+    let mut serialized = vec![];
+    value.consensus_serialize(&mut serialized).unwrap();
+    let metadata_hex = to_hex(&serialized[..]);
+
+    let decoded = decode_metadata(&metadata_hex).expect("valid hex should decode");
+    assert_eq!(decoded, format!("{}", value));
+}
+
+#[test]
+fn metadata_cache_is_populated_lazily_and_invalidated_on_reorg() {
+    let bns = boot_code_id("bns", true);
+    let db = AtlasDB::connect(":memory:", true).expect("in-memory AtlasDB should connect");
+
+    let value = Value::UInt(7);
+    let mut serialized = vec![];
+    value.consensus_serialize(&mut serialized).unwrap();
+
+    let content_hash = Hash160::from_data(b"some attachment");
+    let mut instance = test_instance(bns, content_hash);
+    instance.metadata = to_hex(&serialized[..]);
+    instance.consensus_hash = ConsensusHash([9u8; 20]);
+
+    assert!(db.get_cached_metadata(&content_hash).unwrap().is_none());
+
+    let decoded = db.get_or_decode_metadata(&instance).expect("should decode and cache");
+    assert_eq!(decoded.decoded_metadata, format!("{}", value));
+
+    // A second call with corrupted metadata on the instance must still
+    // succeed, because the cache (keyed by content_hash) is consulted first.
+    instance.metadata = "not valid hex".to_string();
+    let cached = db
+        .get_or_decode_metadata(&instance)
+        .expect("cache hit should not need to re-decode");
+    assert_eq!(cached, decoded);
+
+    db.invalidate_cache_for_reorg(&ConsensusHash([9u8; 20])).unwrap();
+    assert!(db.get_cached_metadata(&content_hash).unwrap().is_none());
+}
+
+#[test]
+fn inventory_counts_ignore_reannounced_duplicate_rows() {
+    let bns = boot_code_id("bns", true);
+    let db = AtlasDB::connect(":memory:", true).expect("in-memory AtlasDB should connect");
+
+    let attachment = Attachment::new(b"slot zero content".to_vec());
+    db.insert_attachment(&attachment).unwrap();
+
+    // Slot 0 gets re-announced across two different forks/blocks -- two rows
+    // in `attachment_instances`, same `position_in_page`.
+    let mut instance_a = test_instance(bns.clone(), attachment.merkle_root());
+    instance_a.position_in_page = 0;
+    instance_a.consensus_hash = ConsensusHash([1u8; 20]);
+    db.insert_instantiated_attachment(&instance_a).unwrap();
+
+    let mut instance_b = instance_a.clone();
+    instance_b.consensus_hash = ConsensusHash([2u8; 20]);
+    db.insert_instantiated_attachment(&instance_b).unwrap();
+
+    // Slot 1 is known on chain but its content was never fetched.
+    let mut instance_c = test_instance(bns.clone(), Hash160::empty());
+    instance_c.position_in_page = 1;
+    db.insert_instantiated_attachment(&instance_c).unwrap();
+
+    let inventory = db
+        .get_attachments_inventory(&[(bns, 0)])
+        .expect("inventory query should succeed")
+        .remove(0);
+
+    assert_eq!(inventory.present_count, 1, "only slot 0's content is locally available");
+    assert_eq!(inventory.total_count, 2, "two distinct slots, despite three instance rows");
+    assert!(inventory.bitmap[0]);
+    assert!(!inventory.bitmap[1]);
+}
+
+#[test]
+fn attachment_marf_key_is_deterministic_and_distinguishes_slots() {
+    let bns = boot_code_id("bns", true);
+    let key_a = attachment_marf_key(&bns, 0, 0);
+    let key_b = attachment_marf_key(&bns, 0, 0);
+    assert_eq!(key_a, key_b, "same inputs must derive the same MARF key");
+
+    // Varying either the page or the position in the page must change the
+    // derived key -- otherwise two distinct attachment slots would alias the
+    // same MARF path.
+    assert_ne!(key_a, attachment_marf_key(&bns, 1, 0));
+    assert_ne!(key_a, attachment_marf_key(&bns, 0, 1));
+
+    // Varying the contract must change the key too.
+    let other = boot_code_id("pox", true);
+    assert_ne!(key_a, attachment_marf_key(&other, 0, 0));
+}
+
+#[test]
+fn recover_padded_leaf_reads_from_root_map() {
+    // Three real chunks pads up to a leaf count of 4: index 3 is padding.
+    let content = vec![0u8; ATTACHMENTS_CHUNK_SIZE * 2 + 1];
+    let attachment = Attachment::new(content);
+    assert_eq!(attachment.chunks().len(), 3);
+
+    assert!(AttachmentsDownloader::recover_padded_leaf(&attachment, 2).is_none());
+    let recovered = AttachmentsDownloader::recover_padded_leaf(&attachment, 3)
+        .expect("index 3 is padding and should be recoverable from the root map");
+    assert_eq!(recovered, Hash160::empty());
+}